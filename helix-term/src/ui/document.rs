@@ -109,6 +109,66 @@ pub struct LinePos {
 
 pub type TranslatedPosition<'a> = (usize, Box<dyn FnMut(&mut TextRenderer, Position) + 'a>);
 
+/// Tracks virtual text that has overflowed `viewport_right_edge` and been
+/// soft-wrapped onto continuation rows, so that `render_text` can keep
+/// every grapheme's visual position correct across the wrap. Kept as a
+/// free-standing, `Document`/`Theme`-free type so the row/column
+/// bookkeeping can be unit tested in isolation.
+#[derive(Default)]
+struct VirtualTextWrapState {
+    /// Running total of extra visual rows consumed so far. Never reset:
+    /// once a continuation row has been painted, every row below it (on
+    /// this line and all following lines) has to be shifted down to stay
+    /// off of it.
+    row_offset: usize,
+    /// The column the next grapheme should continue from, because it
+    /// either is itself virtual text mid-run or is the first non-virtual
+    /// grapheme (typically the line's terminating newline) right after a
+    /// wrapped run. `None` once that correction has been consumed.
+    col: Option<usize>,
+}
+
+impl VirtualTextWrapState {
+    /// Adjusts `pos` for the current grapheme and returns the corrected
+    /// position. `wrapped` in the returned tuple is `true` exactly when
+    /// this call caused a new continuation row to be started, so the
+    /// caller knows to paint the continuation indent.
+    fn advance(
+        &mut self,
+        mut pos: Position,
+        is_virtual: bool,
+        grapheme_width: usize,
+        col_offset: usize,
+        viewport_width: usize,
+        wrap_indent: u16,
+    ) -> (Position, bool) {
+        pos.row += self.row_offset;
+        if let Some(col) = self.col {
+            pos.col = col;
+        }
+
+        if !is_virtual {
+            // Consume (at most) a pending correction for this one real
+            // grapheme, then let the formatter's own column drive things
+            // again from the next grapheme onward.
+            self.col = None;
+            return (pos, false);
+        }
+
+        let viewport_right_edge = col_offset + viewport_width - 1;
+        let continuation_col = col_offset + wrap_indent as usize;
+        let wrapped = pos.col > viewport_right_edge;
+        if wrapped {
+            self.row_offset += 1;
+            pos.row += 1;
+            pos.col = continuation_col;
+        }
+        self.col = Some(pos.col + grapheme_width);
+
+        (pos, wrapped)
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn render_document(
     surface: &mut Surface,
@@ -118,11 +178,23 @@ pub fn render_document(
     doc_annotations: &TextAnnotations,
     syntax_highlight_iter: impl Iterator<Item = HighlightEvent>,
     overlay_highlight_iter: impl Iterator<Item = HighlightEvent>,
+    eol_highlight_iter: impl Iterator<Item = HighlightEvent>,
     theme: &Theme,
     line_decoration: &mut [Box<dyn LineDecoration + '_>],
     translated_positions: &mut [TranslatedPosition],
+    // Visual column of the view's primary cursor, used to highlight the
+    // indent guide the cursor currently sits within. `None` if the view
+    // rendering this document is not focused.
+    cursor_col: Option<usize>,
 ) {
-    let mut renderer = TextRenderer::new(surface, doc, theme, offset.horizontal_offset, viewport);
+    let mut renderer = TextRenderer::new(
+        surface,
+        doc,
+        theme,
+        offset.horizontal_offset,
+        viewport,
+        cursor_col,
+    );
     render_text(
         &mut renderer,
         doc.text().slice(..),
@@ -131,6 +203,7 @@ pub fn render_document(
         doc_annotations,
         syntax_highlight_iter,
         overlay_highlight_iter,
+        eol_highlight_iter,
         theme,
         line_decoration,
         translated_positions,
@@ -180,6 +253,14 @@ pub fn render_text<'t>(
     text_annotations: &TextAnnotations,
     syntax_highlight_iter: impl Iterator<Item = HighlightEvent>,
     overlay_highlight_iter: impl Iterator<Item = HighlightEvent>,
+    // Highlights that should fill the rest of a line up to the viewport
+    // edge once it ends (block selections, cursorline, full-line
+    // diagnostic backgrounds). Kept separate from `overlay_highlight_iter`
+    // so that ordinary token-level overlays which merely happen to end at
+    // end-of-line (a cursor on the last column, a search match or
+    // diagnostic underline on the last word) are never mistaken for a
+    // highlight meant to span the whole row.
+    eol_highlight_iter: impl Iterator<Item = HighlightEvent>,
     theme: &Theme,
     line_decorations: &mut [Box<dyn LineDecoration + '_>],
     translated_positions: &mut [TranslatedPosition],
@@ -216,6 +297,14 @@ pub fn render_text<'t>(
         theme,
         text,
     };
+    let mut eol_styles = StyleIter {
+        text_style: Style::default(),
+        active_highlights: Vec::with_capacity(64),
+        highlight_iter: eol_highlight_iter,
+        kind: StyleIterKind::Overlay,
+        theme,
+        text,
+    };
 
     let mut last_line_pos = LinePos {
         first_visual_line: false,
@@ -225,12 +314,22 @@ pub fn render_text<'t>(
     };
     let mut is_in_indent_area = true;
     let mut last_line_indent_level = 0;
+    // The active `eol_style` and the column just past the last grapheme drawn
+    // on the line currently being built, so that once the line is known to be
+    // complete we can fill the remaining columns with that style (used for
+    // block selections, cursorline and full-line diagnostic backgrounds).
+    let mut last_line_eol_style = Style::default();
+    let mut last_line_end_col = 0;
+    let mut virtual_text_wrap = VirtualTextWrapState::default();
     let mut syntax_style_span = syntax_styles
         .next()
         .unwrap_or_else(|| (Style::default(), usize::MAX));
     let mut overlay_style_span = overlay_styles
         .next()
         .unwrap_or_else(|| (Style::default(), usize::MAX));
+    let mut eol_style_span = eol_styles
+        .next()
+        .unwrap_or_else(|| (Style::default(), usize::MAX));
 
     loop {
         // formattter.line_pos returns to line index of the next grapheme
@@ -270,12 +369,40 @@ pub fn render_text<'t>(
                     break;
                 }
             }
+            if char_pos >= eol_style_span.1 {
+                eol_style_span = if let Some(eol_style_span) = eol_styles.next() {
+                    eol_style_span
+                } else {
+                    break;
+                }
+            }
             char_pos += grapheme.doc_chars();
             first_visible_char_idx = char_pos + 1;
             continue;
         }
         pos.row -= row_off;
 
+        // Long virtual text (inline diagnostics, type hints, ...) can run
+        // past the edge of the viewport; the formatter does not account for
+        // this since it only wraps real document text. Soft-wrap it here
+        // onto continuation rows indented by `virtual_text_wrap_indent`
+        // instead of letting it overflow or get silently clipped. This also
+        // corrects the column of the non-virtual grapheme that immediately
+        // follows a wrapped run (typically the line's terminating newline),
+        // which otherwise would keep the formatter's raw, un-wrapped column.
+        let (wrapped_pos, wrapped) = virtual_text_wrap.advance(
+            pos,
+            grapheme.is_virtual(),
+            grapheme.grapheme.width(),
+            renderer.col_offset,
+            renderer.viewport.width as usize,
+            renderer.virtual_text_wrap_indent,
+        );
+        pos = wrapped_pos;
+        if wrapped {
+            renderer.draw_virtual_text_wrap_indent(pos.row as u16);
+        }
+
         // if the end of the viewport is reached stop rendering
         if pos.row as u16 >= renderer.viewport.height {
             break;
@@ -285,6 +412,17 @@ pub fn render_text<'t>(
         if pos.row as u16 != last_line_pos.visual_line {
             if pos.row > 0 {
                 renderer.draw_indent_guides(last_line_indent_level, last_line_pos.visual_line);
+                // Only extend the overlay background to the viewport edge once
+                // we know we just finished the *final* visual line of a
+                // document line; intermediate soft-wrapped rows still have
+                // more text coming and must not be filled.
+                if doc_line != last_line_pos.doc_line {
+                    renderer.fill_end_of_line(
+                        last_line_eol_style,
+                        last_line_end_col,
+                        last_line_pos.visual_line,
+                    );
+                }
                 is_in_indent_area = true;
                 for line_decoration in &mut *line_decorations {
                     line_decoration.render_foreground(renderer, last_line_pos, char_pos);
@@ -312,6 +450,9 @@ pub fn render_text<'t>(
                 .next()
                 .unwrap_or((Style::default(), usize::MAX));
         }
+        if char_pos >= eol_style_span.1 {
+            eol_style_span = eol_styles.next().unwrap_or((Style::default(), usize::MAX));
+        }
         char_pos += grapheme.doc_chars();
 
         // check if any positions translated on the fly (like cursor) has been reached
@@ -336,6 +477,7 @@ pub fn render_text<'t>(
             };
 
         let is_virtual = grapheme.is_virtual();
+        let grapheme_width = grapheme.grapheme.width();
         renderer.draw_grapheme(
             grapheme.grapheme,
             GraphemeStyle {
@@ -347,9 +489,16 @@ pub fn render_text<'t>(
             &mut is_in_indent_area,
             pos,
         );
+        last_line_eol_style = eol_style_span.0;
+        last_line_end_col = pos.col + grapheme_width;
     }
 
     renderer.draw_indent_guides(last_line_indent_level, last_line_pos.visual_line);
+    renderer.fill_end_of_line(
+        last_line_eol_style,
+        last_line_end_col,
+        last_line_pos.visual_line,
+    );
     for line_decoration in &mut *line_decorations {
         line_decoration.render_foreground(renderer, last_line_pos, char_pos);
     }
@@ -363,6 +512,23 @@ pub struct TextRenderer<'a> {
     pub trailing_whitespace_style: Style,
     pub indent_guide_char: String,
     pub indent_guide_style: Style,
+    /// Per-depth indent guide styles, resolved from the theme keys
+    /// `ui.virtual.indent-guide.1`, `.2`, ... and cycled by depth so that
+    /// deeply nested code gets a distinct color per level ("rainbow"
+    /// indent guides). Empty if the theme does not define any, in which
+    /// case every level falls back to `indent_guide_style`.
+    pub indent_guide_styles: Vec<Style>,
+    /// Style used for the indent guide belonging to the cursor's current
+    /// indent level, so that scope is easier to pick out. `None` disables
+    /// the extra highlight (the theme does not define
+    /// `ui.virtual.indent-guide.cursor`).
+    pub indent_guide_cursor_style: Option<Style>,
+    /// The indent level the primary cursor currently sits at, in units of
+    /// `indent_width`, derived from the cursor column passed into
+    /// `TextRenderer::new`; compared against `i` in `draw_indent_guides` to
+    /// decide which guide gets `indent_guide_cursor_style`. `None` if no
+    /// cursor column was supplied (e.g. the view has no focus).
+    pub cursor_indent_level: Option<usize>,
     pub newline: String,
     pub nbsp: String,
     pub nnbsp: String,
@@ -372,6 +538,14 @@ pub struct TextRenderer<'a> {
     pub indent_width: u16,
     pub starting_indent: usize,
     pub draw_indent_guides: bool,
+    /// Style used for the continuation indent painted in front of a
+    /// virtual-text segment that has been soft-wrapped onto following rows.
+    pub virtual_text_wrap_style: Style,
+    /// Width, in columns, of the continuation indent for wrapped virtual
+    /// text. Sourced from the document's configured indent width, like
+    /// `indent_width` above, so it can actually be configured per-document
+    /// rather than being a fixed constant.
+    pub virtual_text_wrap_indent: u16,
     pub col_offset: usize,
     pub viewport: Rect,
     pub trailing_whitespace_tracker: TrailingWhitespaceTracker,
@@ -389,6 +563,7 @@ impl<'a> TextRenderer<'a> {
         theme: &Theme,
         col_offset: usize,
         viewport: Rect,
+        cursor_col: Option<usize>,
     ) -> TextRenderer<'a> {
         let editor_config = doc.config.load();
 
@@ -401,6 +576,13 @@ impl<'a> TextRenderer<'a> {
         let trailing_ws = WhitespaceFeature::Trailing.palette(ws, tab_width);
         let trailing_whitespace_tracker = TrailingWhitespaceTracker::new(ws.render, trailing_ws);
 
+        let mut indent_guide_styles = Vec::new();
+        let mut level = 1;
+        while let Some(style) = theme.try_get_exact(&format!("ui.virtual.indent-guide.{level}")) {
+            indent_guide_styles.push(text_style.patch(style));
+            level += 1;
+        }
+
         TextRenderer {
             surface,
             indent_guide_char: editor_config.indent_guides.character.into(),
@@ -421,8 +603,20 @@ impl<'a> TextRenderer<'a> {
                     .try_get("ui.virtual.indent-guide")
                     .unwrap_or_else(|| theme.get("ui.virtual.whitespace")),
             ),
+            indent_guide_styles,
+            indent_guide_cursor_style: theme
+                .try_get_exact("ui.virtual.indent-guide.cursor")
+                .map(|style| text_style.patch(style)),
+            cursor_indent_level: cursor_col.map(|col| col / indent_width as usize),
             text_style,
             draw_indent_guides: editor_config.indent_guides.render,
+            virtual_text_wrap_style: theme
+                .try_get("ui.virtual.wrap")
+                .unwrap_or_else(|| theme.get("ui.virtual.whitespace")),
+            // Reuse the document's own indent width rather than a hardcoded
+            // constant, so the continuation indent lines up with the code
+            // it annotates and stays configurable alongside it.
+            virtual_text_wrap_indent: indent_width,
             viewport,
             col_offset,
             trailing_whitespace_tracker,
@@ -530,6 +724,51 @@ impl<'a> TextRenderer<'a> {
         }
     }
 
+    /// Paints the continuation indent in front of a wrapped virtual-text
+    /// segment at the start of visual `row`, using `virtual_text_wrap_style`.
+    fn draw_virtual_text_wrap_indent(&mut self, row: u16) {
+        if self.virtual_text_wrap_indent == 0 || row >= self.viewport.height {
+            return;
+        }
+
+        let rect = Rect::new(
+            self.viewport.x,
+            self.viewport.y + row,
+            self.virtual_text_wrap_indent,
+            1,
+        );
+        self.surface.set_style(rect, self.virtual_text_wrap_style);
+    }
+
+    /// Fills the columns from `start_col` to the right edge of the viewport
+    /// with `style`, on the visual `row`. `style` is expected to come from
+    /// the dedicated end-of-line highlight stream (see `eol_highlight_iter`
+    /// on `render_text`), not from a token-level overlay, so that a
+    /// highlight explicitly meant to span the whole row (block selections,
+    /// cursorline, full-line diagnostic backgrounds) extends across the
+    /// rest of the row instead of stopping at the last grapheme, without
+    /// bleeding an ordinary overlay that merely happens to end at
+    /// end-of-line (a cursor on the last column, a trailing search match).
+    pub fn fill_end_of_line(&mut self, style: Style, start_col: usize, row: u16) {
+        if style == Style::default() {
+            return;
+        }
+
+        let viewport_right_edge = self.viewport.width as usize + self.col_offset - 1;
+        let start_col = start_col.max(self.col_offset);
+        if start_col > viewport_right_edge {
+            return;
+        }
+
+        let rect = Rect::new(
+            self.viewport.x + (start_col - self.col_offset) as u16,
+            self.viewport.y + row,
+            (viewport_right_edge - start_col + 1) as u16,
+            1,
+        );
+        self.surface.set_style(rect, style);
+    }
+
     /// Overlay indentation guides ontop of a rendered line
     /// The indentation level is computed in `draw_lines`.
     /// Therefore this function must always be called afterwards.
@@ -551,8 +790,143 @@ impl<'a> TextRenderer<'a> {
                 as u16;
             let y = self.viewport.y + row;
             debug_assert!(self.surface.in_bounds(x, y));
+
+            let style = if self.cursor_indent_level == Some(i) {
+                self.indent_guide_cursor_style.unwrap_or_else(|| {
+                    if self.indent_guide_styles.is_empty() {
+                        self.indent_guide_style
+                    } else {
+                        self.indent_guide_styles[i % self.indent_guide_styles.len()]
+                    }
+                })
+            } else if self.indent_guide_styles.is_empty() {
+                self.indent_guide_style
+            } else {
+                self.indent_guide_styles[i % self.indent_guide_styles.len()]
+            };
+
             self.surface
-                .set_string(x, y, &self.indent_guide_char, self.indent_guide_style);
+                .set_string(x, y, &self.indent_guide_char, style);
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Regression test for a wrapped virtual-text run corrupting the
+    // position of the grapheme that follows it (e.g. a line-ending
+    // selection/cursorline highlight being pushed out of the viewport and
+    // silently dropped by `fill_end_of_line`). `TextRenderer` itself needs
+    // a `Document`/`Theme` to construct, so this drives the underlying
+    // `VirtualTextWrapState` bookkeeping directly instead.
+    #[test]
+    fn virtual_text_wrap_corrects_trailing_grapheme_column() {
+        let col_offset = 0;
+        let viewport_width = 5;
+        let wrap_indent = 2;
+        let viewport_right_edge = col_offset + viewport_width - 1;
+        let mut wrap = VirtualTextWrapState::default();
+
+        // Virtual graphemes within the viewport are left untouched.
+        let (pos, wrapped) = wrap.advance(
+            Position { row: 0, col: 3 },
+            true,
+            1,
+            col_offset,
+            viewport_width,
+            wrap_indent,
+        );
+        assert!(!wrapped);
+        assert_eq!(pos, Position { row: 0, col: 3 });
+
+        let (pos, wrapped) = wrap.advance(
+            Position { row: 0, col: 4 },
+            true,
+            1,
+            col_offset,
+            viewport_width,
+            wrap_indent,
+        );
+        assert!(!wrapped);
+        assert_eq!(pos, Position { row: 0, col: 4 });
+
+        // The grapheme that overflows the viewport wraps onto a
+        // continuation row, indented by `wrap_indent`.
+        let (pos, wrapped) = wrap.advance(
+            Position { row: 0, col: 5 },
+            true,
+            1,
+            col_offset,
+            viewport_width,
+            wrap_indent,
+        );
+        assert!(wrapped);
+        assert_eq!(
+            pos,
+            Position {
+                row: 1,
+                col: wrap_indent as usize
+            }
+        );
+
+        // A later virtual grapheme in the same run continues from the
+        // wrapped column rather than the formatter's raw, un-wrapped one.
+        let (pos, wrapped) = wrap.advance(
+            Position { row: 0, col: 6 },
+            true,
+            1,
+            col_offset,
+            viewport_width,
+            wrap_indent,
+        );
+        assert!(!wrapped);
+        assert_eq!(
+            pos,
+            Position {
+                row: 1,
+                col: wrap_indent as usize + 1
+            }
+        );
+
+        // The non-virtual grapheme that ends the line (e.g. the
+        // terminating newline, which is what a line-ending selection or
+        // cursorline highlight is keyed off of) must continue from the
+        // wrapped position too. Before this fix it kept the formatter's
+        // original column here, which is far past the viewport edge and
+        // made `fill_end_of_line` bail out (`start_col > viewport_right_edge`),
+        // silently dropping the highlight on any line with wrapped virtual
+        // text.
+        let (pos, wrapped) = wrap.advance(
+            Position { row: 0, col: 7 },
+            false,
+            1,
+            col_offset,
+            viewport_width,
+            wrap_indent,
+        );
+        assert!(!wrapped);
+        assert_eq!(pos.row, 1);
+        assert!(
+            pos.col <= viewport_right_edge,
+            "trailing grapheme column {} must stay within the viewport (edge {})",
+            pos.col,
+            viewport_right_edge
+        );
+
+        // The row offset from the wrap is never reset, so a plain real
+        // grapheme on the following document line still stays shifted
+        // down below the continuation row that was painted.
+        let (pos, wrapped) = wrap.advance(
+            Position { row: 1, col: 0 },
+            false,
+            1,
+            col_offset,
+            viewport_width,
+            wrap_indent,
+        );
+        assert!(!wrapped);
+        assert_eq!(pos, Position { row: 2, col: 0 });
+    }
+}